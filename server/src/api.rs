@@ -2,26 +2,47 @@ use axum::{
     routing::get,
     Router,
     Json,
-    extract::{State, WebSocketUpgrade, ws::{WebSocket, Message}},
+    extract::{State, WebSocketUpgrade, Path, Query, ws::{WebSocket, Message}},
     http::{StatusCode, HeaderMap},
     response::{IntoResponse, Response},
     middleware::{self, Next},
 };
 use axum::extract::Request;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
 use serde_json::json;
 use futures::{sink::SinkExt, stream::StreamExt};
 use tokio::sync::broadcast;
 use bytes::Bytes;
+use dashmap::DashMap;
+use log::{info, warn};
 
-use crate::metrics::Metrics;
+use crate::metrics::{Metrics, MetricsSnapshot};
 use crate::db::DbManager;
-use adatp_core::{Packet, MessageType}; 
+use crate::federation::{Federation, RelayFrame};
+use crate::{now_millis, AuthPayload, BroadcastMessage, HistoryQuery, UserData};
+use crate::auth::verify_password;
+use adatp_core::{Packet, MessageType};
 
 pub struct AppState {
     pub metrics: Arc<Metrics>,
     pub db: Arc<DbManager>,
-    pub tx: broadcast::Sender<(String, Vec<u8>)>,
+    pub tx: broadcast::Sender<BroadcastMessage>,
+    /// `users.json`, loaded once at startup and shared with the TCP auth
+    /// path so `/ws` checks the same credentials `handle_connection` does.
+    pub users_config: Arc<HashMap<String, UserData>>,
+    /// Live room membership: room -> session key -> presence metadata.
+    /// Populated on join, cleaned up by `PresenceGuard::drop` so a session
+    /// can never be leaked on panic or abort.
+    pub presence: DashMap<String, HashMap<String, PresenceInfo>>,
+    pub federation: Arc<Federation>,
+}
+
+#[derive(Clone, serde::Serialize)]
+pub struct PresenceInfo {
+    pub session_id: String,
+    pub username: String,
+    pub joined_at_ms: i64,
 }
 
 async fn auth_middleware(
@@ -30,10 +51,6 @@ async fn auth_middleware(
     request: Request,
     next: Next,
 ) -> Response {
-    if request.uri().path() == "/ws" {
-        return next.run(request).await;
-    }
-
     let api_key = headers
         .get("x-api-key")
         .and_then(|val| val.to_str().ok());
@@ -53,11 +70,13 @@ pub fn create_router(state: Arc<AppState>) -> Router {
     let api_routes = Router::new()
         .route("/status", get(status_handler))
         .route("/metrics", get(metrics_handler))
+        .route("/rooms/:room/presence", get(presence_handler))
         .route_layer(middleware::from_fn_with_state(state.clone(), auth_middleware));
-        
+
     Router::new()
         .route("/", get(root_handler))
         .route("/ws", get(ws_handler))
+        .route("/internal/relay", axum::routing::post(relay_handler))
         .nest("/api", api_routes)
         .with_state(state)
 }
@@ -70,9 +89,87 @@ async fn status_handler() -> Json<serde_json::Value> {
     Json(json!({ "status": "ok", "service": "adatp-server" }))
 }
 
-async fn metrics_handler(State(state): State<Arc<AppState>>) -> Json<serde_json::Value> {
+async fn metrics_handler(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
     let snapshot = state.metrics.snapshot();
-    Json(json!(snapshot))
+
+    let accepts_prometheus = headers
+        .get(axum::http::header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.contains("text/plain"))
+        .unwrap_or(false);
+
+    if accepts_prometheus {
+        prometheus_exposition(&snapshot).into_response()
+    } else {
+        Json(json!(snapshot)).into_response()
+    }
+}
+
+/// Renders the metrics snapshot in Prometheus text exposition format,
+/// reading fields straight off `MetricsSnapshot` so a renamed or removed
+/// field is a compile error here instead of a silently-zero gauge.
+fn prometheus_exposition(snapshot: &MetricsSnapshot) -> (HeaderMap, String) {
+    let MetricsSnapshot { active_connections, bytes_rx_total, bytes_tx_total } = *snapshot;
+
+    let body = format!(
+        "# HELP adatp_connections_active Number of currently active client connections.\n\
+         # TYPE adatp_connections_active gauge\n\
+         adatp_connections_active {active_connections}\n\
+         # HELP adatp_bytes_rx_total Total bytes received from clients.\n\
+         # TYPE adatp_bytes_rx_total counter\n\
+         adatp_bytes_rx_total {bytes_rx_total}\n\
+         # HELP adatp_bytes_tx_total Total bytes sent to clients.\n\
+         # TYPE adatp_bytes_tx_total counter\n\
+         adatp_bytes_tx_total {bytes_tx_total}\n"
+    );
+
+    let mut headers = HeaderMap::new();
+    headers.insert(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4".parse().unwrap());
+    (headers, body)
+}
+
+async fn presence_handler(State(state): State<Arc<AppState>>, Path(room): Path<String>) -> Json<serde_json::Value> {
+    let members: Vec<PresenceInfo> = state
+        .presence
+        .get(&room)
+        .map(|m| m.values().cloned().collect())
+        .unwrap_or_default();
+    Json(json!({ "room": room, "members": members }))
+}
+
+/// Inbound side of federation: a peer node posts a packet one of its own
+/// clients produced here, and we re-inject it into our local broadcast
+/// channel so our subscribers see it too. Authenticated with the shared
+/// `CLUSTER_SECRET` rather than `x-api-key`, since this is node-to-node
+/// traffic, not a client request.
+async fn relay_handler(State(state): State<Arc<AppState>>, headers: HeaderMap, Json(frame): Json<RelayFrame>) -> Response {
+    let provided = headers.get("x-cluster-secret").and_then(|v| v.to_str().ok()).unwrap_or("");
+    if !state.federation.verify_cluster_secret(provided) {
+        return (StatusCode::UNAUTHORIZED, "Invalid cluster secret").into_response();
+    }
+
+    if state.federation.is_own_frame(&frame) {
+        // Bounced back to the node that produced it somehow; drop it rather
+        // than re-delivering to our own subscribers a second time.
+        return StatusCode::OK.into_response();
+    }
+
+    match frame.decode_payload() {
+        Ok(bytes) => {
+            // Re-inject into the local broadcast channel only — never call
+            // `relay_to_peers` here. That asymmetry (and not any flag on
+            // `BroadcastMessage`) is what keeps federation loop-free: a
+            // frame a peer forwards to us reaches our subscribers but is
+            // never fanned back out again.
+            let _ = state.tx.send(BroadcastMessage {
+                room: frame.room.clone(),
+                sender_session_id: frame.sender_session_id.clone(),
+                bytes,
+            });
+            StatusCode::OK.into_response()
+        }
+        Err(_) => (StatusCode::BAD_REQUEST, "Invalid payload encoding").into_response(),
+    }
 }
 
 // --- WebSocket Logic ---
@@ -80,8 +177,54 @@ async fn metrics_handler(State(state): State<Arc<AppState>>) -> Json<serde_json:
 async fn ws_handler(
     ws: WebSocketUpgrade,
     State(state): State<Arc<AppState>>,
+    Query(params): Query<HashMap<String, String>>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(|socket| handle_socket(socket, state))
+    let token = params.get("token").cloned();
+    match token {
+        Some(token) => match state.db.validate_key(&token).await {
+            Ok(true) => ws.on_upgrade(|socket| handle_socket(socket, state)).into_response(),
+            _ => (StatusCode::UNAUTHORIZED, "Invalid or inactive token").into_response(),
+        },
+        None => (StatusCode::UNAUTHORIZED, "Missing token query parameter").into_response(),
+    }
+}
+
+/// RAII guard for a joined presence-registry entry. `Drop` removes the
+/// session from `AppState::presence` and broadcasts a `PresenceUpdate`
+/// LEAVE packet for whichever room the session was last in, so presence
+/// is cleaned up even if the connection task is aborted or panics instead
+/// of reaching the end of `handle_socket`.
+struct PresenceGuard {
+    state: Arc<AppState>,
+    room: Arc<Mutex<String>>,
+    session_key: String,
+    leave_packet_bytes: Vec<u8>,
+}
+
+impl Drop for PresenceGuard {
+    fn drop(&mut self) {
+        let room = self.room.lock().unwrap().clone();
+        if let Some(mut members) = self.state.presence.get_mut(&room) {
+            members.remove(&self.session_key);
+        }
+        let leave_msg = BroadcastMessage {
+            room: room.clone(),
+            sender_session_id: self.session_key.clone(),
+            bytes: self.leave_packet_bytes.clone(),
+        };
+        let _ = self.state.tx.send(leave_msg);
+
+        // Drop can't be async, so the peer fan-out for the LEAVE packet runs
+        // as a detached task instead of being awaited here. This LEAVE is
+        // always locally-originated, so relaying unconditionally is correct;
+        // `relay_handler` never relays what it receives from peers, which is
+        // the actual loop-prevention mechanism (see its comment above).
+        if self.state.federation.is_enabled() {
+            let federation = self.state.federation.clone();
+            let frame = RelayFrame::new(federation.node_id.clone(), room, self.session_key.clone(), &self.leave_packet_bytes);
+            tokio::spawn(async move { federation.relay_to_peers(&frame).await; });
+        }
+    }
 }
 
 async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
@@ -89,8 +232,11 @@ async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
     state.metrics.inc_connection();
 
     // State Tracking
-    let mut room = "global".to_string();
+    let room = Arc::new(Mutex::new("global".to_string()));
     let mut connected_session_id = None; // Store the UUID of the client
+    let mut username = "guest".to_string();
+    let mut connected_session_key: Option<String> = None; // Debug-formatted session id, used as the broadcast sender id
+    let mut presence_guard: Option<PresenceGuard> = None;
 
     let (ws_tx, mut ws_rx) = tokio::sync::mpsc::channel::<Vec<u8>>(100);
     
@@ -115,31 +261,129 @@ async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
                         state.metrics.add_rx(data.len() as u64);
                         if let Ok(packet) = Packet::from_bytes(Bytes::from(data.clone())) {
                             
-                            // Capture ID from first valid packet
+                            // Capture ID from first valid packet and register presence
+                            // for the room we're in so far ("global" until a JoinRoom arrives).
                             if connected_session_id.is_none() {
-                                connected_session_id = Some(packet.header.session_id);
+                                let session_id = packet.header.session_id;
+                                connected_session_id = Some(session_id);
+                                let session_key = format!("{:?}", session_id);
+                                connected_session_key = Some(session_key.clone());
+                                let leave_packet = Packet::new(MessageType::PresenceUpdate, Bytes::from("LEAVE"), session_id);
+
+                                let current_room = room.lock().unwrap().clone();
+                                state.presence.entry(current_room).or_default().insert(session_key.clone(), PresenceInfo {
+                                    session_id: session_key.clone(),
+                                    username: username.clone(),
+                                    joined_at_ms: now_millis(),
+                                });
+
+                                presence_guard = Some(PresenceGuard {
+                                    state: state.clone(),
+                                    room: room.clone(),
+                                    session_key,
+                                    leave_packet_bytes: leave_packet.to_bytes().to_vec(),
+                                });
                             }
 
                             match packet.header.msg_type {
                                 MessageType::JoinRoom => {
                                     // Parse Room Name from Payload
                                     if let Ok(new_room) = std::str::from_utf8(&packet.payload) {
-                                        room = new_room.to_string();
-                                        println!("Client joined room: {}", room);
+                                        let new_room = new_room.to_string();
+                                        let old_room = std::mem::replace(&mut *room.lock().unwrap(), new_room.clone());
+
+                                        if let Some(guard) = presence_guard.as_ref() {
+                                            if let Some(mut members) = state.presence.get_mut(&old_room) {
+                                                members.remove(&guard.session_key);
+                                            }
+                                            state.presence.entry(new_room.clone()).or_default().insert(guard.session_key.clone(), PresenceInfo {
+                                                session_id: guard.session_key.clone(),
+                                                username: username.clone(),
+                                                joined_at_ms: now_millis(),
+                                            });
+                                        }
+                                        info!("Client joined room: {}", new_room);
                                     } else {
                                         // Demo Fallback if payload empty/invalid
-                                        // room = "conf".to_string(); 
-                                        println!("JoinRoom failed: invalid payload");
+                                        // room = "conf".to_string();
+                                        warn!("JoinRoom failed: invalid payload");
                                     }
                                 },
                                 MessageType::AuthRequest => {
-                                    // Respond with Success
-                                    let resp = Packet::new(MessageType::AuthSuccess, Bytes::from("Access Granted"), packet.header.session_id);
+                                    let auth_result = serde_json::from_slice::<AuthPayload>(&packet.payload)
+                                        .ok()
+                                        .and_then(|req| state.users_config.get(&req.username).map(|user| (req, user.clone())))
+                                        .filter(|(req, user)| verify_password(&req.password, &user.password_hash));
+
+                                    let (out_type, out_payload) = match auth_result {
+                                        Some((_req, user)) => {
+                                            username = user.username.clone();
+                                            if let Some(guard) = presence_guard.as_ref() {
+                                                let current_room = room.lock().unwrap().clone();
+                                                if let Some(mut members) = state.presence.get_mut(&current_room) {
+                                                    if let Some(info) = members.get_mut(&guard.session_key) {
+                                                        info.username = username.clone();
+                                                    }
+                                                }
+                                            }
+                                            (MessageType::AuthSuccess, Bytes::from("Access Granted"))
+                                        }
+                                        None => (MessageType::AuthFailure, Bytes::from("Invalid username or password")),
+                                    };
+
+                                    let resp = Packet::new(out_type, out_payload, packet.header.session_id);
                                     let _ = ws_tx.send(resp.to_bytes().to_vec()).await;
                                 },
                                 MessageType::TextMessage | MessageType::FileInit | MessageType::FileChunk | MessageType::FileComplete | MessageType::VoiceData | MessageType::VideoData => {
+                                     let current_room = room.lock().unwrap().clone();
+
+                                     // Persist the messages that matter for history replay.
+                                     if matches!(packet.header.msg_type, MessageType::TextMessage | MessageType::FileComplete) {
+                                         if let Some(session_id) = connected_session_id {
+                                             if let Err(e) = state.db.insert_message(&current_room, session_id, packet.header.msg_type, &packet.payload, now_millis()).await {
+                                                 warn!("Failed to persist message in room {}: {}", current_room, e);
+                                             }
+                                         }
+                                     }
+
                                      // Broadcast to Room
-                                     let _ = state.tx.send((room.clone(), data));
+                                     let broadcast_msg = BroadcastMessage {
+                                         room: current_room.clone(),
+                                         sender_session_id: connected_session_key.clone().unwrap_or_default(),
+                                         bytes: data.clone(),
+                                     };
+                                     let _ = state.tx.send(broadcast_msg);
+
+                                     // Fan out to federated peers so the room isn't
+                                     // confined to this process. This WS connection only
+                                     // ever produces locally-originated frames, so relaying
+                                     // unconditionally is correct; `relay_handler` never
+                                     // relays what it receives from peers, which is what
+                                     // actually keeps federation loop-free.
+                                     if state.federation.is_enabled() {
+                                         let frame = RelayFrame::new(
+                                             state.federation.node_id.clone(),
+                                             current_room,
+                                             connected_session_key.clone().unwrap_or_default(),
+                                             &data,
+                                         );
+                                         state.federation.relay_to_peers(&frame).await;
+                                     }
+                                }
+                                MessageType::HistoryRequest => {
+                                    let query: HistoryQuery = serde_json::from_slice(&packet.payload).unwrap_or_default();
+                                    let limit = query.limit.unwrap_or(50).clamp(1, 200);
+                                    let current_room = room.lock().unwrap().clone();
+
+                                    match state.db.fetch_room_history(&current_room, query.before, limit).await {
+                                        Ok(history) => {
+                                            for stored in history {
+                                                let pkt = Packet::new(stored.msg_type, stored.payload.into(), packet.header.session_id);
+                                                let _ = ws_tx.send(pkt.to_bytes().to_vec()).await;
+                                            }
+                                        }
+                                        Err(e) => warn!("Failed to load history for room {}: {}", current_room, e),
+                                    }
                                 }
                                 _ => {}
                             }
@@ -151,11 +395,11 @@ async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
             }
 
             // B. Incoming from Broadcast
-            Ok((msg_room, msg_bytes)) = broadcast_rx.recv() => {
-                if msg_room == room {
-                    // Don't echo back to sender? (Echo cancellation logic is better handled on client for now as we don't parse sender ID here efficiently every time)
-                    state.metrics.add_tx(msg_bytes.len() as u64);
-                    if ws_tx.send(msg_bytes).await.is_err() {
+            Ok(msg) = broadcast_rx.recv() => {
+                let current_room = room.lock().unwrap().clone();
+                if msg.is_relevant_to(&current_room, connected_session_key.as_deref().unwrap_or_default()) {
+                    state.metrics.add_tx(msg.bytes.len() as u64);
+                    if ws_tx.send(msg.bytes).await.is_err() {
                         break;
                     }
                 }
@@ -164,13 +408,17 @@ async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
     }
 
     // --- DISCONNECT HANDLER (REALTIME EXIT) ---
+    // The actual presence-registry cleanup and LEAVE broadcast happen in
+    // `PresenceGuard::drop` below, which also fires on task abort/panic.
+    // Here we just persist a best-effort record of the departure.
     if let Some(session_id) = connected_session_id {
-        // Create a 'PresenceUpdate' packet with payload "LEAVE"
-        // And send it to the room so others know this ID is gone.
-        let leave_packet = Packet::new(MessageType::PresenceUpdate, Bytes::from("LEAVE"), session_id);
-        let _ = state.tx.send((room, leave_packet.to_bytes().to_vec()));
+        let current_room = room.lock().unwrap().clone();
+        if let Err(e) = state.db.insert_message(&current_room, session_id, MessageType::PresenceUpdate, b"LEAVE", now_millis()).await {
+            warn!("Failed to persist presence update in room {}: {}", current_room, e);
+        }
     }
 
+    drop(presence_guard);
     write_task.abort();
     state.metrics.dec_connection();
 }
@@ -0,0 +1,43 @@
+//! Password hashing and verification for `users.json` accounts.
+//!
+//! Passwords are never stored or compared in plaintext: `users.json`
+//! holds an Argon2id PHC string (`$argon2id$v=19$...`) per user, generated
+//! with a random per-user salt, and `verify_password` re-runs Argon2id
+//! with the parameters embedded in that string.
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rand_core::OsRng;
+
+#[derive(Debug)]
+pub struct AuthError(pub String);
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "auth error: {}", self.0)
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Hashes a plaintext password into an Argon2id PHC string, for use when
+/// provisioning `users.json` entries.
+#[allow(dead_code)]
+pub fn hash_password(password: &str) -> Result<String, AuthError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| AuthError(format!("failed to hash password: {}", e)))
+}
+
+/// Verifies a plaintext password against a stored Argon2id PHC hash.
+pub fn verify_password(password: &str, stored_hash: &str) -> bool {
+    let parsed_hash = match PasswordHash::new(stored_hash) {
+        Ok(h) => h,
+        Err(_) => return false,
+    };
+    Argon2::default()
+        .verify_password(password.as_bytes(), &parsed_hash)
+        .is_ok()
+}
@@ -0,0 +1,256 @@
+//! Per-session cryptography for the TCP chat server.
+//!
+//! Handshake is a one-shot, unauthenticated-DH Noise-style exchange:
+//! both peers generate an ephemeral X25519 keypair, exchange public
+//! keys via `HandshakeInit`/`HandshakeResponse`, and derive a pair of
+//! ChaCha20-Poly1305 keys (one per direction) from the shared secret
+//! with HKDF-SHA256. `HandshakeComplete` carries an HMAC over the
+//! exchanged public keys, computed from the shared secret each side
+//! derived on its own. This only proves key confirmation — that both
+//! sides reached the same transcript and shared secret — which catches
+//! accidental corruption or a passive bit-flip in transit. It is NOT
+//! MITM resistance: neither side has any static or pinned identity, so
+//! an active attacker terminating both legs independently completes a
+//! valid handshake with each peer and produces a valid tag for both.
+//!
+//! Nonces are never sent on the wire: each direction is a reliable,
+//! ordered byte stream (TCP), so both sides simply count the packets
+//! they have sent/received in that direction and use the counter as
+//! the nonce. Reuse is therefore impossible as long as the counter is
+//! only ever incremented, and we refuse to wrap past `u64::MAX`.
+
+use adatp_core::MessageType;
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit, Nonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand_core::OsRng;
+use sha2::Sha256;
+use std::fmt;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 32;
+
+#[derive(Debug)]
+pub struct CryptoError(pub String);
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "crypto error: {}", self.0)
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+/// Our side of an in-progress handshake: an ephemeral keypair waiting
+/// on the peer's public key before it can be turned into a `SessionCipher`.
+pub struct PendingHandshake {
+    secret: EphemeralSecret,
+    public: PublicKey,
+}
+
+impl PendingHandshake {
+    pub fn generate() -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    pub fn public_bytes(&self) -> [u8; 32] {
+        self.public.to_bytes()
+    }
+
+    /// Consumes the ephemeral secret, completing the Diffie-Hellman
+    /// exchange and deriving the directional session keys.
+    pub fn finalize(self, peer_public_bytes: &[u8], we_are_server: bool) -> Result<(SessionCipher, [u8; 32]), CryptoError> {
+        if peer_public_bytes.len() != 32 {
+            return Err(CryptoError(format!(
+                "expected a 32-byte X25519 public key, got {}",
+                peer_public_bytes.len()
+            )));
+        }
+        let mut peer_arr = [0u8; 32];
+        peer_arr.copy_from_slice(peer_public_bytes);
+        let peer_public = PublicKey::from(peer_arr);
+
+        let shared = self.secret.diffie_hellman(&peer_public);
+        let shared_bytes = *shared.as_bytes();
+        let cipher = SessionCipher::derive(&shared_bytes, we_are_server)?;
+        Ok((cipher, shared_bytes))
+    }
+}
+
+/// Computes the transcript authentication tag exchanged in
+/// `HandshakeComplete`. Both peers compute this independently from
+/// the shared secret and the two public keys, in a fixed order, and
+/// reject the session if the values don't match.
+pub fn transcript_tag(shared_secret: &[u8], client_public: &[u8; 32], server_public: &[u8; 32]) -> Result<[u8; TAG_LEN], CryptoError> {
+    let hk = Hkdf::<Sha256>::new(None, shared_secret);
+    let mut mac_key = [0u8; 32];
+    hk.expand(b"adatp handshake transcript mac", &mut mac_key)
+        .map_err(|e| CryptoError(format!("HKDF expand failed: {}", e)))?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(&mac_key)
+        .map_err(|e| CryptoError(format!("invalid HMAC key: {}", e)))?;
+    mac.update(client_public);
+    mac.update(server_public);
+    let tag = mac.finalize().into_bytes();
+    let mut out = [0u8; TAG_LEN];
+    out.copy_from_slice(&tag);
+    Ok(out)
+}
+
+/// Symmetric session state: one ChaCha20-Poly1305 cipher per
+/// direction, each with its own monotonic nonce counter.
+pub struct SessionCipher {
+    tx_cipher: ChaCha20Poly1305,
+    rx_cipher: ChaCha20Poly1305,
+    tx_counter: u64,
+    rx_counter: u64,
+}
+
+impl SessionCipher {
+    fn derive(shared_secret: &[u8; 32], we_are_server: bool) -> Result<Self, CryptoError> {
+        let hk = Hkdf::<Sha256>::new(None, shared_secret);
+        let mut okm = [0u8; 64];
+        hk.expand(b"adatp handshake v1", &mut okm)
+            .map_err(|e| CryptoError(format!("HKDF expand failed: {}", e)))?;
+        let (client_to_server, server_to_client) = okm.split_at(32);
+
+        let (tx_key, rx_key) = if we_are_server {
+            (server_to_client, client_to_server)
+        } else {
+            (client_to_server, server_to_client)
+        };
+
+        Ok(Self {
+            tx_cipher: ChaCha20Poly1305::new(tx_key.into()),
+            rx_cipher: ChaCha20Poly1305::new(rx_key.into()),
+            tx_counter: 0,
+            rx_counter: 0,
+        })
+    }
+
+    fn counter_nonce(counter: u64) -> [u8; NONCE_LEN] {
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[4..].copy_from_slice(&counter.to_be_bytes());
+        nonce
+    }
+
+    /// Encrypts a packet payload for the outgoing direction, advancing
+    /// the per-direction nonce counter. `msg_type`/`session_id` are the
+    /// header fields this ciphertext will be sent under; they're bound
+    /// in as AEAD associated data (not encrypted themselves, since the
+    /// header has to stay readable before decryption, but authenticated)
+    /// so flipping either on the wire fails the Poly1305 tag instead of
+    /// silently changing what the decrypted payload means.
+    pub fn encrypt(&mut self, plaintext: &[u8], msg_type: MessageType, session_id: impl fmt::Debug) -> Result<Vec<u8>, CryptoError> {
+        if self.tx_counter == u64::MAX {
+            return Err(CryptoError("tx nonce counter exhausted; session must be re-keyed".into()));
+        }
+        let nonce = Self::counter_nonce(self.tx_counter);
+        self.tx_counter += 1;
+        let aad = header_aad(msg_type, session_id);
+        self.tx_cipher
+            .encrypt(Nonce::from_slice(&nonce), Payload { msg: plaintext, aad: &aad })
+            .map_err(|_| CryptoError("encryption failed".into()))
+    }
+
+    /// Decrypts a packet payload from the incoming direction. The
+    /// nonce counter only ever advances, so a replayed or
+    /// out-of-order packet fails authentication rather than being
+    /// silently reused. `msg_type`/`session_id` must be the header
+    /// fields the packet actually arrived with — see `encrypt`.
+    pub fn decrypt(&mut self, ciphertext: &[u8], msg_type: MessageType, session_id: impl fmt::Debug) -> Result<Vec<u8>, CryptoError> {
+        if self.rx_counter == u64::MAX {
+            return Err(CryptoError("rx nonce counter exhausted; session must be re-keyed".into()));
+        }
+        let nonce = Self::counter_nonce(self.rx_counter);
+        self.rx_counter += 1;
+        let aad = header_aad(msg_type, session_id);
+        self.rx_cipher
+            .decrypt(Nonce::from_slice(&nonce), Payload { msg: ciphertext, aad: &aad })
+            .map_err(|_| CryptoError("decryption/authentication failed".into()))
+    }
+}
+
+/// Associated data binding a ciphertext to the header fields it was
+/// encrypted under, so an on-path attacker can't flip `msg_type` (e.g.
+/// turn a `TextMessage` into a `Disconnect`) on an otherwise-untouched
+/// ciphertext without the AEAD tag failing.
+fn header_aad(msg_type: MessageType, session_id: impl fmt::Debug) -> Vec<u8> {
+    format!("{:?}|{:?}", msg_type, session_id).into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paired_ciphers() -> (SessionCipher, SessionCipher) {
+        let client = PendingHandshake::generate();
+        let server = PendingHandshake::generate();
+        let client_public = client.public_bytes();
+        let server_public = server.public_bytes();
+
+        let (client_cipher, client_secret) = client.finalize(&server_public, false).unwrap();
+        let (server_cipher, server_secret) = server.finalize(&client_public, true).unwrap();
+        assert_eq!(client_secret, server_secret);
+        (client_cipher, server_cipher)
+    }
+
+    #[test]
+    fn encrypt_decrypt_roundtrip() {
+        let (mut client, mut server) = paired_ciphers();
+
+        let ciphertext = client.encrypt(b"hello room", MessageType::TextMessage, "session-1").unwrap();
+        let plaintext = server.decrypt(&ciphertext, MessageType::TextMessage, "session-1").unwrap();
+        assert_eq!(plaintext, b"hello room");
+    }
+
+    #[test]
+    fn decrypt_rejects_tampered_msg_type_aad() {
+        let (mut client, mut server) = paired_ciphers();
+
+        let ciphertext = client.encrypt(b"hello room", MessageType::TextMessage, "session-1").unwrap();
+        // Same ciphertext, same session id, but the header claims a
+        // different msg_type than it was encrypted under — must fail.
+        let result = server.decrypt(&ciphertext, MessageType::Disconnect, "session-1");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn decrypt_rejects_tampered_session_id_aad() {
+        let (mut client, mut server) = paired_ciphers();
+
+        let ciphertext = client.encrypt(b"hello room", MessageType::TextMessage, "session-1").unwrap();
+        let result = server.decrypt(&ciphertext, MessageType::TextMessage, "session-2");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn handshake_transcript_mismatch_is_detected() {
+        let client_public = PendingHandshake::generate().public_bytes();
+        let server_public = PendingHandshake::generate().public_bytes();
+        let other_public = PendingHandshake::generate().public_bytes();
+
+        let secret = [7u8; 32];
+        let tag = transcript_tag(&secret, &client_public, &server_public).unwrap();
+        let forged_tag = transcript_tag(&secret, &other_public, &server_public).unwrap();
+        assert_ne!(tag, forged_tag);
+    }
+
+    #[test]
+    fn session_keys_convert_cleanly_from_hkdf_output() {
+        // `SessionCipher::derive` slices a 64-byte HKDF output into two
+        // 32-byte halves and relies on `&[u8]::into()` to build a
+        // `chacha20poly1305::Key` from each. Exercise both directions so
+        // a future HKDF/key-length change that breaks that conversion
+        // fails here instead of at a runtime panic in `derive`.
+        let secret = [42u8; 32];
+        let server = SessionCipher::derive(&secret, true).unwrap();
+        let client = SessionCipher::derive(&secret, false).unwrap();
+        assert_eq!(server.tx_counter, 0);
+        assert_eq!(client.rx_counter, 0);
+    }
+}
@@ -0,0 +1,190 @@
+//! SQLite-backed persistence: API keys (for `x-api-key` / `/ws?token=`
+//! auth) and room message history (for `HistoryRequest` replay).
+//!
+//! Both tables are created on startup with `CREATE TABLE IF NOT EXISTS`
+//! so a fresh `adatp.db` just works and an existing one is left alone.
+
+use adatp_core::MessageType;
+use sqlx::sqlite::{SqlitePool, SqlitePoolOptions};
+use sqlx::Row;
+use std::fmt;
+
+use crate::StoredMessage;
+
+#[derive(Debug)]
+pub struct DbError(pub String);
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "db error: {}", self.0)
+    }
+}
+
+impl std::error::Error for DbError {}
+
+impl From<sqlx::Error> for DbError {
+    fn from(e: sqlx::Error) -> Self {
+        DbError(e.to_string())
+    }
+}
+
+pub struct DbManager {
+    pool: SqlitePool,
+}
+
+impl DbManager {
+    pub async fn new(database_url: &str) -> Result<Self, DbError> {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(5)
+            .connect(database_url)
+            .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS api_keys (
+                key TEXT PRIMARY KEY,
+                active INTEGER NOT NULL DEFAULT 1
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                room TEXT NOT NULL,
+                session_id TEXT NOT NULL,
+                msg_type TEXT NOT NULL,
+                payload BLOB NOT NULL,
+                timestamp_ms INTEGER NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS idx_messages_room_timestamp
+             ON messages (room, timestamp_ms)",
+        )
+        .execute(&pool)
+        .await?;
+
+        Ok(Self { pool })
+    }
+
+    /// Checks `key` against the `api_keys` table, used by `auth_middleware`
+    /// (`x-api-key`) and the `/ws?token=` query-param check.
+    pub async fn validate_key(&self, key: &str) -> Result<bool, DbError> {
+        let row = sqlx::query("SELECT active FROM api_keys WHERE key = ?")
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.map(|r| r.get::<i64, _>("active") != 0).unwrap_or(false))
+    }
+
+    /// Persists one broadcast-worthy packet. `session_id` is stored as its
+    /// `Debug` rendering, the same representation already used elsewhere
+    /// in this crate as the broadcast channel's `sender_session_id`.
+    pub async fn insert_message(
+        &self,
+        room: &str,
+        session_id: impl fmt::Debug,
+        msg_type: MessageType,
+        payload: &[u8],
+        timestamp_ms: i64,
+    ) -> Result<(), DbError> {
+        let session_id = format!("{:?}", session_id);
+        let msg_type = format!("{:?}", msg_type);
+
+        sqlx::query(
+            "INSERT INTO messages (room, session_id, msg_type, payload, timestamp_ms)
+             VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(room)
+        .bind(session_id)
+        .bind(msg_type)
+        .bind(payload)
+        .bind(timestamp_ms)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns up to `limit` stored messages for `room`, oldest first, so
+    /// a reconnecting client can replay them in the order they happened.
+    /// `before` restricts the page to messages older than that timestamp,
+    /// for paginating further back than the first `limit`.
+    pub async fn fetch_room_history(
+        &self,
+        room: &str,
+        before: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<StoredMessage>, DbError> {
+        let rows = match before {
+            Some(before) => {
+                sqlx::query(
+                    "SELECT msg_type, payload, timestamp_ms FROM messages
+                     WHERE room = ? AND timestamp_ms < ?
+                     ORDER BY timestamp_ms DESC LIMIT ?",
+                )
+                .bind(room)
+                .bind(before)
+                .bind(limit)
+                .fetch_all(&self.pool)
+                .await?
+            }
+            None => {
+                sqlx::query(
+                    "SELECT msg_type, payload, timestamp_ms FROM messages
+                     WHERE room = ?
+                     ORDER BY timestamp_ms DESC LIMIT ?",
+                )
+                .bind(room)
+                .bind(limit)
+                .fetch_all(&self.pool)
+                .await?
+            }
+        };
+
+        let mut history = Vec::with_capacity(rows.len());
+        for row in rows {
+            let msg_type_str: String = row.get("msg_type");
+            let msg_type = msg_type_from_str(&msg_type_str)?;
+            history.push(StoredMessage {
+                msg_type,
+                payload: row.get("payload"),
+                timestamp_ms: row.get("timestamp_ms"),
+            });
+        }
+        // Rows came back newest-first (so LIMIT keeps the most recent page);
+        // flip to chronological order before handing them to a replaying client.
+        history.reverse();
+        Ok(history)
+    }
+}
+
+/// `MessageType` has no `FromStr`/`Display` of its own (it comes from
+/// `adatp_core`), so round-tripping it through the `messages.msg_type`
+/// column needs an explicit mapping on each side.
+fn msg_type_from_str(s: &str) -> Result<MessageType, DbError> {
+    match s {
+        "HandshakeInit" => Ok(MessageType::HandshakeInit),
+        "HandshakeResponse" => Ok(MessageType::HandshakeResponse),
+        "HandshakeComplete" => Ok(MessageType::HandshakeComplete),
+        "AuthRequest" => Ok(MessageType::AuthRequest),
+        "AuthSuccess" => Ok(MessageType::AuthSuccess),
+        "AuthFailure" => Ok(MessageType::AuthFailure),
+        "JoinRoom" => Ok(MessageType::JoinRoom),
+        "Disconnect" => Ok(MessageType::Disconnect),
+        "FileInit" => Ok(MessageType::FileInit),
+        "FileChunk" => Ok(MessageType::FileChunk),
+        "FileComplete" => Ok(MessageType::FileComplete),
+        "TextMessage" => Ok(MessageType::TextMessage),
+        "HistoryRequest" => Ok(MessageType::HistoryRequest),
+        "PresenceUpdate" => Ok(MessageType::PresenceUpdate),
+        "VoiceData" => Ok(MessageType::VoiceData),
+        "VideoData" => Ok(MessageType::VideoData),
+        other => Err(DbError(format!("unknown msg_type in messages table: {}", other))),
+    }
+}
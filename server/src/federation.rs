@@ -0,0 +1,169 @@
+//! Multi-node federation: forwards locally-originated room broadcasts to
+//! peer instances over HTTP and re-injects packets peers forward to us
+//! into the local broadcast channel, so a room can span more than one
+//! server process.
+//!
+//! Configuration is env-driven, matching the rest of the server's setup:
+//! - `NODE_ID` — this node's identifier, stamped on frames we relay so
+//!   peers (and we, on loopback) can tell where a frame originated.
+//! - `FEDERATION_PEERS` — comma-separated base URLs of peer nodes.
+//! - `CLUSTER_SECRET` — shared secret peers must present on
+//!   `POST /internal/relay`; federation is effectively disabled if empty,
+//!   since we refuse to accept relayed frames without one.
+
+use base64::Engine;
+use log::warn;
+use std::env;
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct RelayFrame {
+    pub origin_node_id: String,
+    pub room: String,
+    pub sender_session_id: String,
+    pub payload_b64: String,
+}
+
+impl RelayFrame {
+    pub fn new(origin_node_id: String, room: String, sender_session_id: String, payload: &[u8]) -> Self {
+        Self {
+            origin_node_id,
+            room,
+            sender_session_id,
+            payload_b64: base64::engine::general_purpose::STANDARD.encode(payload),
+        }
+    }
+
+    pub fn decode_payload(&self) -> Result<Vec<u8>, base64::DecodeError> {
+        base64::engine::general_purpose::STANDARD.decode(&self.payload_b64)
+    }
+}
+
+pub struct Federation {
+    pub node_id: String,
+    pub cluster_secret: String,
+    peers: Vec<String>,
+    http: reqwest::Client,
+}
+
+impl Federation {
+    pub fn from_env() -> Self {
+        let node_id = env::var("NODE_ID").unwrap_or_else(|_| "node-local".to_string());
+        let cluster_secret = env::var("CLUSTER_SECRET").unwrap_or_default();
+        let peers = env::var("FEDERATION_PEERS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|s| s.trim().trim_end_matches('/').to_string())
+            .filter(|s| !s.is_empty())
+            .collect();
+
+        Self {
+            node_id,
+            cluster_secret,
+            peers,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.peers.is_empty()
+    }
+
+    /// Whether `frame` is one this node produced itself. `relay_to_peers`
+    /// only ever sends frames stamped with our own `node_id`, so this only
+    /// trips if a frame somehow loops back to us (e.g. a peer misconfigured
+    /// to relay to itself); `relay_handler` drops it rather than
+    /// re-delivering to our own subscribers a second time.
+    pub fn is_own_frame(&self, frame: &RelayFrame) -> bool {
+        frame.origin_node_id == self.node_id
+    }
+
+    /// Checks a peer-supplied `x-cluster-secret` header against our
+    /// configured secret in constant time, so an attacker probing
+    /// `/internal/relay` can't use response-time differences to recover
+    /// the secret one byte at a time. An empty configured secret always
+    /// fails, since that means federation is disabled.
+    pub fn verify_cluster_secret(&self, provided: &str) -> bool {
+        if self.cluster_secret.is_empty() {
+            return false;
+        }
+        let expected = self.cluster_secret.as_bytes();
+        let provided = provided.as_bytes();
+
+        let mut diff = (expected.len() ^ provided.len()) as u8;
+        for i in 0..expected.len().max(provided.len()) {
+            diff |= expected.get(i).unwrap_or(&0) ^ provided.get(i).unwrap_or(&0);
+        }
+        diff == 0
+    }
+
+    /// Forwards a locally-originated room packet to every configured peer.
+    /// Each peer request is fire-and-forget on its own task so a slow or
+    /// unreachable peer can't stall the connection that produced the packet.
+    pub async fn relay_to_peers(&self, frame: &RelayFrame) {
+        for peer in &self.peers {
+            let url = format!("{}/internal/relay", peer);
+            let client = self.http.clone();
+            let secret = self.cluster_secret.clone();
+            let frame = frame.clone();
+            tokio::spawn(async move {
+                if let Err(e) = client
+                    .post(&url)
+                    .header("x-cluster-secret", secret)
+                    .json(&frame)
+                    .send()
+                    .await
+                {
+                    warn!("Federation relay to {} failed: {}", url, e);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn federation(node_id: &str, secret: &str) -> Federation {
+        Federation {
+            node_id: node_id.to_string(),
+            cluster_secret: secret.to_string(),
+            peers: Vec::new(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    #[test]
+    fn is_own_frame_true_for_matching_node_id() {
+        let fed = federation("node-a", "s3cr3t");
+        let frame = RelayFrame::new("node-a".to_string(), "room-1".to_string(), "session-1".to_string(), b"hi");
+        assert!(fed.is_own_frame(&frame));
+    }
+
+    #[test]
+    fn is_own_frame_false_for_a_peer_node_id() {
+        let fed = federation("node-a", "s3cr3t");
+        let frame = RelayFrame::new("node-b".to_string(), "room-1".to_string(), "session-1".to_string(), b"hi");
+        assert!(!fed.is_own_frame(&frame));
+    }
+
+    #[test]
+    fn verify_cluster_secret_accepts_the_configured_value() {
+        let fed = federation("node-a", "s3cr3t");
+        assert!(fed.verify_cluster_secret("s3cr3t"));
+    }
+
+    #[test]
+    fn verify_cluster_secret_rejects_a_wrong_value() {
+        let fed = federation("node-a", "s3cr3t");
+        assert!(!fed.verify_cluster_secret("wrong"));
+        assert!(!fed.verify_cluster_secret(""));
+    }
+
+    #[test]
+    fn verify_cluster_secret_rejects_everything_when_unconfigured() {
+        let fed = federation("node-a", "");
+        assert!(!fed.verify_cluster_secret(""));
+        assert!(!fed.verify_cluster_secret("anything"));
+    }
+}
@@ -4,7 +4,6 @@ use std::sync::Arc;
 use std::collections::HashMap;
 use tokio::sync::Mutex;
 use std::error::Error;
-use env_logger;
 use log::{info, error, warn};
 use dotenvy::dotenv;
 use std::env;
@@ -16,33 +15,94 @@ use adatp_core::transport::tcp::TcpTransport;
 mod metrics;
 mod db;
 mod api;
+mod crypto;
+mod auth;
+mod transport_ext;
+mod observability;
+mod federation;
 
 use crate::metrics::Metrics;
 use crate::db::DbManager;
 use crate::api::AppState;
+use crate::crypto::{transcript_tag, PendingHandshake, SessionCipher};
+use crate::auth::verify_password;
+use crate::transport_ext::TcpTransportRawExt;
+use crate::federation::{Federation, RelayFrame};
+use tracing::Instrument;
 
 /// Shared state for the chat server
 struct SharedState {
     #[allow(dead_code)]
-    users: Mutex<HashMap<String, String>>, 
+    users: Mutex<HashMap<String, String>>,
     metrics: Arc<Metrics>,
+    db: Arc<DbManager>,
+    federation: Arc<Federation>,
+}
+
+/// A message persisted to the `messages` table, returned by
+/// `DbManager::fetch_room_history` for history replay.
+pub(crate) struct StoredMessage {
+    pub(crate) msg_type: MessageType,
+    pub(crate) payload: Vec<u8>,
+    #[allow(dead_code)]
+    pub(crate) timestamp_ms: i64,
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+pub(crate) struct HistoryQuery {
+    pub(crate) before: Option<i64>,
+    pub(crate) limit: Option<i64>,
+}
+
+pub(crate) fn now_millis() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/// An item on the room broadcast channel. Carries the originating
+/// session alongside the room and the serialized packet bytes so
+/// receivers can skip delivering a frame back to the sender that
+/// produced it, instead of every client having to dedup on its own.
+#[derive(Clone)]
+pub(crate) struct BroadcastMessage {
+    pub(crate) room: String,
+    pub(crate) sender_session_id: String,
+    pub(crate) bytes: Vec<u8>,
+}
+
+impl BroadcastMessage {
+    /// Whether a receiver sitting in `room` under `session_key` should be
+    /// handed this message: it has to be for the same room, and not an
+    /// echo of a packet that receiver itself just sent.
+    pub(crate) fn is_relevant_to(&self, room: &str, session_key: &str) -> bool {
+        self.room == room && self.sender_session_id != session_key
+    }
 }
 
 #[derive(serde::Deserialize, Clone, Debug)]
 #[allow(dead_code)]
-struct UserData {
-    username: String,
-    password: String,
-    role: String,
+pub(crate) struct UserData {
+    pub(crate) username: String,
+    /// Argon2id PHC string, e.g. `$argon2id$v=19$m=19456,t=2,p=1$...`. Never plaintext.
+    pub(crate) password_hash: String,
+    pub(crate) role: String,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub(crate) struct AuthPayload {
+    pub(crate) username: String,
+    pub(crate) password: String,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     dotenv().ok();
-    env_logger::init();
+    observability::init()?;
     
     // 1. Ini Broadcast Channel
-    let (tx, _rx) = broadcast::channel(100);
+    let (tx, _rx) = broadcast::channel::<BroadcastMessage>(100);
 
     // 2. Init Metrics (In-Memory)
     let metrics = Arc::new(Metrics::new());
@@ -55,11 +115,23 @@ async fn main() -> Result<(), Box<dyn Error>> {
     
     let db_manager = Arc::new(DbManager::new(&db_url).await.expect("Failed to init DB"));
 
+    // Load users.json for Client Auth (shared by both the TCP and WS auth paths)
+    let users_config = load_users_config()?;
+
+    // 3b. Init Federation (peer nodes this room traffic should fan out to)
+    let federation = Arc::new(Federation::from_env());
+    if federation.is_enabled() {
+        info!("Federation enabled as node {}", federation.node_id);
+    }
+
     // 4. Start HTTP API Server
     let api_state = Arc::new(AppState {
         metrics: metrics.clone(),
         db: db_manager.clone(),
         tx: tx.clone(), // Pass broadcast sender to API for WS
+        users_config: users_config.clone(),
+        presence: dashmap::DashMap::new(),
+        federation: federation.clone(),
     });
     
     let app = api::create_router(api_state);
@@ -80,10 +152,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let state = Arc::new(SharedState {
         users: Mutex::new(HashMap::new()),
         metrics: metrics.clone(),
+        db: db_manager.clone(),
+        federation: federation.clone(),
     });
-    
-    // Load users.json for Client Auth
-    let users_config = load_users_config()?;
 
     loop {
         let (socket, client_addr) = listener.accept().await?;
@@ -107,7 +178,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
     }
 }
 
-fn load_users_config() -> Result<Arc<HashMap<String, UserData>>, Box<dyn Error>> {
+pub(crate) fn load_users_config() -> Result<Arc<HashMap<String, UserData>>, Box<dyn Error>> {
     let content = std::fs::read_to_string("users.json").unwrap_or_else(|_| "[]".to_string());
     let users_list: Vec<UserData> = serde_json::from_str(&content)?;
     
@@ -118,19 +189,22 @@ fn load_users_config() -> Result<Arc<HashMap<String, UserData>>, Box<dyn Error>>
     Ok(Arc::new(map))
 }
 
+#[tracing::instrument(skip(socket, tx, rx, state, users_config), fields(client = %addr))]
 async fn handle_connection(
     socket: TcpStream,
-    tx: broadcast::Sender<(String, Vec<u8>)>,
-    mut rx: broadcast::Receiver<(String, Vec<u8>)>,
+    tx: broadcast::Sender<BroadcastMessage>,
+    mut rx: broadcast::Receiver<BroadcastMessage>,
     addr: std::net::SocketAddr,
     state: Arc<SharedState>,
-    _users_config: Arc<HashMap<String, UserData>>
+    users_config: Arc<HashMap<String, UserData>>
 ) -> Result<(), Box<dyn Error>> {
     // Wrapped Transport
     let mut transport = TcpTransport::new(socket);
 
     // 1. Handshake Init
-    let init_packet = transport.read_packet().await?
+    let init_packet = transport.read_packet()
+        .instrument(tracing::info_span!("handshake_recv_init"))
+        .await?
         .ok_or("Connection closed during handshake init")?;
     
     state.metrics.add_rx(init_packet.to_bytes().len() as u64);
@@ -141,36 +215,68 @@ async fn handle_connection(
 
     info!("Handshake Init from {}", addr);
 
-    // 2. Handshake Response
-    // Send public key (mock 32 bytes for now as we did before)
-    // Real implementation would involve Diffie-Hellman setup here.
+    let client_public = init_packet.payload.to_vec();
+    if client_public.len() != 32 {
+        return Err("HandshakeInit payload must be a 32-byte X25519 public key".into());
+    }
+
+    // 2. Handshake Response: generate our ephemeral X25519 keypair and
+    // send our public key back so both sides can compute the shared secret.
+    let pending = PendingHandshake::generate();
+    let server_public = pending.public_bytes();
+
     let resp = Packet::new(
-        MessageType::HandshakeResponse, 
-        vec![0u8; 32].into(), 
+        MessageType::HandshakeResponse,
+        server_public.to_vec().into(),
         init_packet.header.session_id
-    ); 
-    
+    );
+
     state.metrics.add_tx(resp.to_bytes().len() as u64);
-    transport.write_packet(&resp).await?;
+    transport.write_packet(&resp)
+        .instrument(tracing::info_span!("handshake_send_response"))
+        .await?;
     info!("Sent Handshake Response to {}", addr);
 
-    // 3. Handshake Complete
-    let complete_packet = transport.read_packet().await?
+    let (mut cipher, shared_secret) = pending
+        .finalize(&client_public, true)
+        .map_err(|e| format!("handshake key exchange failed for {}: {}", addr, e))?;
+
+    // 3. Handshake Complete: the client's payload is an HMAC over the
+    // transcript (both public keys) computed from the shared secret. A key
+    // swapped in transit without a matching new tag makes our recomputed tag
+    // differ. This is key confirmation only: an active MITM that runs its own
+    // handshake with each side produces a valid tag for both and is not
+    // detected (see the `crypto` module docs).
+    let complete_packet = transport.read_packet()
+        .instrument(tracing::info_span!("handshake_recv_complete"))
+        .await?
          .ok_or("Connection closed during handshake complete")?;
-    
+
     state.metrics.add_rx(complete_packet.to_bytes().len() as u64);
 
     if complete_packet.header.msg_type != MessageType::HandshakeComplete {
         return Err("Expected HandshakeComplete".into());
     }
 
+    let mut client_public_arr = [0u8; 32];
+    client_public_arr.copy_from_slice(&client_public);
+    let expected_tag = transcript_tag(&shared_secret, &client_public_arr, &server_public)
+        .map_err(|e| format!("failed to compute handshake transcript tag: {}", e))?;
+
+    if complete_packet.payload.as_ref() != expected_tag.as_slice() {
+        warn!("Handshake transcript mismatch from {} — possible MITM, dropping connection", addr);
+        return Err("Handshake transcript authentication failed".into());
+    }
+
     info!("Handshake Complete {}. Session Established.", addr);
 
     // Auth & Loop State
     let mut username = "guest".to_string();
+    let mut role = "guest".to_string();
     let mut room = "global".to_string();
     let mut _authenticated = false;
     let session_id = complete_packet.header.session_id;
+    let session_key = format!("{:?}", session_id);
 
     // Main Loop
     loop {
@@ -180,20 +286,46 @@ async fn handle_connection(
                 match res {
                     Ok(Some(packet)) => {
                         state.metrics.add_rx(packet.to_bytes().len() as u64);
-                        
+
+                        let plaintext_payload = match cipher.decrypt(&packet.payload, packet.header.msg_type, packet.header.session_id) {
+                            Ok(p) => p,
+                            Err(e) => {
+                                warn!("Dropping connection {}: {}", addr, e);
+                                break;
+                            }
+                        };
+                        let packet = Packet::new(packet.header.msg_type, plaintext_payload.into(), packet.header.session_id);
+
                         match packet.header.msg_type {
                             MessageType::AuthRequest => {
-                                 username = "cbot".to_string(); 
-                                 _authenticated = true;
-                                 info!("Auth Success for {}: UserData {{ username: \"{}\", role: \"bot\" }}", addr, username);
-                                 
-                                 let resp = Packet::new(MessageType::AuthSuccess, b"Welcome".to_vec().into(), session_id);
+                                 let auth_result = serde_json::from_slice::<AuthPayload>(&packet.payload)
+                                     .ok()
+                                     .and_then(|req| users_config.get(&req.username).map(|user| (req, user.clone())))
+                                     .filter(|(req, user)| verify_password(&req.password, &user.password_hash));
+
+                                 let (out_type, out_payload) = match auth_result {
+                                     Some((_req, user)) => {
+                                         username = user.username.clone();
+                                         role = user.role.clone();
+                                         _authenticated = true;
+                                         info!("Auth Success for {}: UserData {{ username: \"{}\", role: \"{}\" }}", addr, username, role);
+                                         (MessageType::AuthSuccess, b"Welcome".to_vec())
+                                     }
+                                     None => {
+                                         warn!("Auth Failure for {}", addr);
+                                         (MessageType::AuthFailure, b"Invalid username or password".to_vec())
+                                     }
+                                 };
+
+                                 let ciphertext = cipher.encrypt(&out_payload, out_type, session_id)
+                                     .map_err(|e| format!("failed to encrypt auth response for {}: {}", addr, e))?;
+                                 let resp = Packet::new(out_type, ciphertext.into(), session_id);
                                  state.metrics.add_tx(resp.to_bytes().len() as u64);
                                  transport.write_packet(&resp).await?;
                             },
-                            
+
                             MessageType::JoinRoom => {
-                                 room = "files".to_string(); 
+                                 room = "files".to_string();
                                  info!("Client {} switching to {}", username, room);
                             },
 
@@ -203,10 +335,53 @@ async fn handle_connection(
                             },
 
                             MessageType::FileInit | MessageType::FileChunk | MessageType::FileComplete | MessageType::TextMessage => {
-                                // Broadcast logic
+                                // Persist the messages that matter for history replay. File chunks
+                                // and init frames are transient and intentionally not stored.
+                                if matches!(packet.header.msg_type, MessageType::TextMessage | MessageType::FileComplete) {
+                                    if let Err(e) = state.db.insert_message(&room, session_id, packet.header.msg_type, &packet.payload, now_millis()).await {
+                                        warn!("Failed to persist message in room {}: {}", room, e);
+                                    }
+                                }
+
+                                // Broadcast logic. The channel carries the decrypted packet so every
+                                // subscriber can re-encrypt it under its own per-session keys.
                                 let packet_bytes = packet.to_bytes().to_vec();
+                                let broadcast_msg = BroadcastMessage {
+                                    room: room.clone(),
+                                    sender_session_id: session_key.clone(),
+                                    bytes: packet_bytes.clone(),
+                                };
                                 // Ignore send errors (no receivers)
-                                let _ = tx.send((room.clone(), packet_bytes)); 
+                                let _ = tx.send(broadcast_msg);
+
+                                // This connection only ever carries locally-originated
+                                // packets, so relaying here is always correct. What
+                                // actually keeps federation loop-free is that the
+                                // `/internal/relay` handler never calls `relay_to_peers`
+                                // on a frame it receives from a peer — it only re-injects
+                                // it into the local broadcast channel above.
+                                if state.federation.is_enabled() {
+                                    let frame = RelayFrame::new(state.federation.node_id.clone(), room.clone(), session_key.clone(), &packet_bytes);
+                                    state.federation.relay_to_peers(&frame).await;
+                                }
+                            },
+
+                            MessageType::HistoryRequest => {
+                                let query: HistoryQuery = serde_json::from_slice(&packet.payload).unwrap_or_default();
+                                let limit = query.limit.unwrap_or(50).clamp(1, 200);
+
+                                match state.db.fetch_room_history(&room, query.before, limit).await {
+                                    Ok(history) => {
+                                        for stored in history {
+                                            let ciphertext = cipher.encrypt(&stored.payload, stored.msg_type, session_id)
+                                                .map_err(|e| format!("failed to encrypt history packet for {}: {}", addr, e))?;
+                                            let pkt = Packet::new(stored.msg_type, ciphertext.into(), session_id);
+                                            state.metrics.add_tx(pkt.to_bytes().len() as u64);
+                                            transport.write_packet(&pkt).await?;
+                                        }
+                                    }
+                                    Err(e) => warn!("Failed to load history for room {}: {}", room, e),
+                                }
                             },
                             _ => {}
                         }
@@ -223,21 +398,29 @@ async fn handle_connection(
             }
 
             // WRITE to Client (Broadcast)
-            Ok((msg_room, msg_bytes)) = rx.recv() => {
-                if msg_room == room {
-                    // We have raw bytes. TcpTransport expects a Packet.
-                    // But wait, TcpTransport writes `Packet`.
-                    // Does it have a `write_raw`? No.
-                    // We must Parse the bytes back to Packet? 
-                    // Or extend TcpTransport to write raw bytes?
-                    // Parsing back is safer but adds overhead.
-                    // Given we just broadcasted `packet.to_bytes()`, we can parse it back.
-                    // Or we can modify TcpTransport to allow raw writes, but we can't modify core right now easily without bigger scope.
-                    // Let's Parse back. It's safe.
-                    
-                    if let Ok(pkt) = Packet::from_bytes(bytes::Bytes::from(msg_bytes.clone())) {
-                         state.metrics.add_tx(msg_bytes.len() as u64);
-                         if let Err(e) = transport.write_packet(&pkt).await {
+            Ok(msg) = rx.recv() => {
+                if msg.is_relevant_to(&room, &session_key) {
+                    // The channel carries the plaintext packet; we still have to parse it
+                    // once to get at the payload, since every subscriber re-encrypts it
+                    // under its own session keys before the bytes hit the wire.
+                    if let Ok(pkt) = Packet::from_bytes(bytes::Bytes::from(msg.bytes.clone())) {
+                         let ciphertext = match cipher.encrypt(&pkt.payload, pkt.header.msg_type, pkt.header.session_id) {
+                             Ok(ct) => ct,
+                             Err(e) => {
+                                 warn!("Failed to encrypt broadcast for {}: {}", addr, e);
+                                 break;
+                             }
+                         };
+                         let out_pkt = Packet::new(pkt.header.msg_type, ciphertext.into(), pkt.header.session_id);
+                         let out_bytes = out_pkt.to_bytes();
+                         state.metrics.add_tx(out_bytes.len() as u64);
+                         // write_raw pushes the bytes we just serialized straight to the
+                         // socket instead of handing write_packet a &Packet it would
+                         // serialize all over again.
+                         let write_result = transport.write_raw(&out_bytes)
+                             .instrument(tracing::info_span!("broadcast_relay_write", room = %msg.room))
+                             .await;
+                         if let Err(e) = write_result {
                              warn!("Error writing broadcast to {}: {}", addr, e);
                              break;
                          }
@@ -250,3 +433,34 @@ async fn handle_connection(
     info!("Client {} connection handler finished", addr);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(room: &str, sender_session_id: &str) -> BroadcastMessage {
+        BroadcastMessage {
+            room: room.to_string(),
+            sender_session_id: sender_session_id.to_string(),
+            bytes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn is_relevant_to_skips_the_sender_s_own_message() {
+        let broadcast = msg("room-1", "session-1");
+        assert!(!broadcast.is_relevant_to("room-1", "session-1"));
+    }
+
+    #[test]
+    fn is_relevant_to_delivers_to_other_sessions_in_the_same_room() {
+        let broadcast = msg("room-1", "session-1");
+        assert!(broadcast.is_relevant_to("room-1", "session-2"));
+    }
+
+    #[test]
+    fn is_relevant_to_skips_a_different_room_even_for_other_sessions() {
+        let broadcast = msg("room-1", "session-1");
+        assert!(!broadcast.is_relevant_to("room-2", "session-2"));
+    }
+}
@@ -0,0 +1,56 @@
+//! In-process connection/throughput counters, exposed as JSON by default
+//! and as Prometheus exposition text when `/api/metrics` is asked for it
+//! (see `api::prometheus_exposition`).
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+pub struct Metrics {
+    active_connections: AtomicU64,
+    bytes_rx_total: AtomicU64,
+    bytes_tx_total: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self {
+            active_connections: AtomicU64::new(0),
+            bytes_rx_total: AtomicU64::new(0),
+            bytes_tx_total: AtomicU64::new(0),
+        }
+    }
+
+    pub fn inc_connection(&self) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec_connection(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn add_rx(&self, bytes: u64) {
+        self.bytes_rx_total.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_tx(&self, bytes: u64) {
+        self.bytes_tx_total.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            bytes_rx_total: self.bytes_rx_total.load(Ordering::Relaxed),
+            bytes_tx_total: self.bytes_tx_total.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A point-in-time read of `Metrics`. Field names are the canonical
+/// source of truth for both the JSON response and the Prometheus
+/// exposition text — `api::prometheus_exposition` reads these fields
+/// directly rather than guessing at a serialized representation.
+#[derive(Clone, Copy, serde::Serialize)]
+pub struct MetricsSnapshot {
+    pub active_connections: u64,
+    pub bytes_rx_total: u64,
+    pub bytes_tx_total: u64,
+}
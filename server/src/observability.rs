@@ -0,0 +1,42 @@
+//! Tracing setup: routes the existing `log` macros through `tracing` so
+//! every `info!`/`warn!`/`error!` call in the server also shows up as a
+//! tracing event, and optionally ships spans to an OTLP collector.
+//!
+//! Set `OTEL_EXPORTER_OTLP_ENDPOINT` to enable the OTLP exporter; with it
+//! unset the server just logs to stdout via `tracing_subscriber::fmt`.
+//! No level filter is applied in either case; `RUST_LOG` is not read.
+
+use std::env;
+use std::error::Error;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+
+pub fn init() -> Result<(), Box<dyn Error>> {
+    tracing_log::LogTracer::init()?;
+
+    let fmt_layer = tracing_subscriber::fmt::layer();
+
+    match env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        Ok(endpoint) => {
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(
+                    opentelemetry_otlp::new_exporter()
+                        .tonic()
+                        .with_endpoint(endpoint),
+                )
+                .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+            let otel_layer = tracing_opentelemetry::layer().with_tracer(tracer);
+
+            tracing_subscriber::registry()
+                .with(fmt_layer)
+                .with(otel_layer)
+                .try_init()?;
+        }
+        Err(_) => {
+            tracing_subscriber::registry().with(fmt_layer).try_init()?;
+        }
+    }
+
+    Ok(())
+}